@@ -0,0 +1,98 @@
+//! Wayland client connections
+//!
+//! A `Client` is a lightweight handle to one of the connections accepted by
+//! the listening socket(s) of a `Display`. It is mostly useful to compare
+//! resources for same-client ownership (see `Resource::same_client_as`) and
+//! to post a disconnection.
+
+#[cfg(feature = "native_lib")]
+use wayland_sys::server::wl_client;
+
+#[cfg(feature = "rust_impl")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "rust_impl")]
+use imp::rust_impl::ClientConnection;
+#[cfg(feature = "rust_impl")]
+use user_data::UserData;
+
+/// A handle to a client connected to this display
+#[derive(Clone)]
+pub struct Client {
+    #[cfg(feature = "native_lib")]
+    ptr: *mut wl_client,
+    #[cfg(feature = "rust_impl")]
+    inner: Arc<Mutex<ClientConnection>>,
+}
+
+#[cfg(feature = "native_lib")]
+impl Client {
+    /// Create a `Client` from a native pointer
+    ///
+    /// The pointer must be a valid `wl_client` pointer as provided by
+    /// `libwayland-server.so`.
+    pub unsafe fn from_ptr(ptr: *mut wl_client) -> Client {
+        Client { ptr }
+    }
+
+    /// Pointer to the underlying `wl_client`
+    pub fn ptr(&self) -> *mut wl_client {
+        self.ptr
+    }
+}
+
+#[cfg(feature = "rust_impl")]
+impl Client {
+    /// Create a `Client` from an already-accepted connection
+    pub(crate) fn from_connection(inner: Arc<Mutex<ClientConnection>>) -> Client {
+        Client { inner }
+    }
+
+    /// Post a protocol error to this client
+    ///
+    /// Only the `wl_display.error` event is sent; the connection itself is
+    /// not closed here.
+    pub(crate) fn post_error(&self, object_id: u32, error_code: u32, msg: String) {
+        use imp::rust_impl::Argument;
+        // The error event is always sent through the `wl_display` singleton,
+        // whose object id is 1 on every connection.
+        let args = vec![
+            Argument::Object(object_id),
+            Argument::Uint(error_code),
+            Argument::Str(msg),
+        ];
+        let mut inner = self.inner.lock().unwrap();
+        let _ = inner.send_event(1, 0, &args);
+    }
+
+    /// The `UserData` slot of one of this client's resources
+    pub(crate) fn resource_user_data(&self, object_id: u32) -> &UserData {
+        let mut inner = self.inner.lock().unwrap();
+        let ptr = inner.user_data_entry(object_id) as *mut UserData;
+        // SAFETY: `user_data_entry` hands out a pointer into a `Box` that
+        // is never moved nor freed before the object is unregistered, so
+        // it stays valid for as long as this `Client` (and the resource it
+        // backs) is alive.
+        unsafe { &*ptr }
+    }
+
+    /// Lock and access the underlying connection
+    ///
+    /// Used by the event loop to register dispatchers and route requests,
+    /// and by `Queue` to re-dispatch requests buffered for later.
+    pub(crate) fn connection(&self) -> ::std::sync::MutexGuard<'_, ClientConnection> {
+        self.inner.lock().unwrap()
+    }
+}
+
+impl PartialEq for Client {
+    fn eq(&self, other: &Client) -> bool {
+        #[cfg(feature = "native_lib")]
+        {
+            self.ptr == other.ptr
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            Arc::ptr_eq(&self.inner, &other.inner)
+        }
+    }
+}
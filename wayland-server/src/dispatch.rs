@@ -0,0 +1,45 @@
+//! Central state-struct dispatch
+//!
+//! This is an alternative to registering a per-object `Implementation`
+//! struct plus a `StateToken` to reach your shared state: instead, your
+//! whole compositor state is a single type `D` that implements `Dispatch<I>`
+//! for every interface `I` it wants to handle. `StateEventLoop::dispatch`
+//! borrows `&mut D` for the whole duration of the call, so every `request`
+//! invocation gets direct access to the rest of your state, with no tokens
+//! or implementation-data plumbing required.
+//!
+//! Globals for such a state are registered with
+//! `EventLoopHandle::register_global_dispatch`, which requires `D` to
+//! implement `Dispatch<I>` for the global's interface. Binding such a global
+//! does not call `Dispatch::request`: its bind callback only looks up `D`
+//! through `EventLoopHandle::dispatch_state`.
+//!
+//! While `StateEventLoop::dispatch` is running, `D` is reachable from
+//! inside a generated `Implementable::__dispatch_msg` body through
+//! `EventLoopHandle::dispatch_state`, which is how that method resolves
+//! and calls the matching `Dispatch<I>::request`.
+
+use Client;
+use EventLoopHandle;
+use Resource;
+
+/// Implemented by a central compositor state type for every interface `I`
+/// it wants to handle through the `Dispatch` routing path
+///
+/// `Implementable::__dispatch_msg` decodes the raw wire arguments into
+/// `Self::Request` and calls this method on whichever state is currently
+/// driving the event loop.
+pub trait Dispatch<I: Resource>: Sized + 'static {
+    /// The decoded request enum for `I`, as produced by the scanner from
+    /// its XML description
+    type Request;
+
+    /// Handle a single incoming request targeting `resource`
+    fn request(
+        &mut self,
+        resource: &I,
+        request: Self::Request,
+        client: &Client,
+        evlh: &mut EventLoopHandle,
+    );
+}
@@ -0,0 +1,215 @@
+//! The wayland display: the server side of the listening socket(s)
+
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::io::RawFd;
+
+use event_loop::EventLoop;
+
+#[cfg(feature = "native_lib")]
+use std::ffi::CString;
+#[cfg(feature = "native_lib")]
+use wayland_sys::server::*;
+
+#[cfg(feature = "rust_impl")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "rust_impl")]
+use imp::rust_impl::ServerListener;
+
+/// First `wayland-N` name tried by `add_socket_auto`
+const SOCKET_NAME_MIN: u32 = 0;
+/// Last `wayland-N` name tried by `add_socket_auto`
+const SOCKET_NAME_MAX: u32 = 32;
+
+/// The wayland display
+///
+/// This is the main entry point to advertise a listening socket to clients
+/// and flush pending events to them.
+pub struct Display {
+    #[cfg(feature = "native_lib")]
+    ptr: *mut wl_display,
+    #[cfg(feature = "rust_impl")]
+    listeners: Arc<Mutex<Vec<ServerListener>>>,
+}
+
+impl Display {
+    /// Flush events queued for the clients to their respective sockets
+    pub fn flush_clients(&mut self) {
+        #[cfg(feature = "native_lib")]
+        unsafe {
+            ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_display_flush_clients, self.ptr);
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            // The rust backend keeps no outgoing buffer: `send_event` writes
+            // each event to the client's socket right away.
+        }
+    }
+
+    /// Add a listening socket under the given name in `$XDG_RUNTIME_DIR`
+    pub fn add_socket<S: AsRef<::std::ffi::OsStr>>(&mut self, name: S) -> io::Result<()> {
+        #[cfg(feature = "native_lib")]
+        {
+            let cstring = CString::new(name.as_ref().to_string_lossy().into_owned())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let ret = unsafe {
+                ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_display_add_socket, self.ptr, cstring.as_ptr())
+            };
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            let path = runtime_dir()?.join(name.as_ref());
+            let listener = ServerListener::bind(path)?;
+            self.listeners.lock().unwrap().push(listener);
+            Ok(())
+        }
+    }
+
+    /// Probe `wayland-0` through `wayland-32` under `$XDG_RUNTIME_DIR`,
+    /// bind the first free one, export it as `$WAYLAND_DISPLAY` and return
+    /// its name
+    ///
+    /// This mirrors the client-side ergonomics of `connect_to_env`: a
+    /// compositor does not need to reimplement socket-name picking itself.
+    pub fn add_socket_auto(&mut self) -> io::Result<OsString> {
+        for i in SOCKET_NAME_MIN..=SOCKET_NAME_MAX {
+            let name = format!("wayland-{}", i);
+            match self.add_socket(&name) {
+                Ok(()) => {
+                    ::std::env::set_var("WAYLAND_DISPLAY", &name);
+                    return Ok(OsString::from(name));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "no free wayland-N socket name found",
+        ))
+    }
+
+    /// Add a listening socket from an already-bound fd, as used for
+    /// socket-activation setups where systemd (or similar) hands the fd to
+    /// the process
+    pub unsafe fn add_socket_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        #[cfg(feature = "native_lib")]
+        {
+            let ret = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_display_add_socket_fd, self.ptr, fd);
+            if ret == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            let listener = ServerListener::from_raw_fd(fd);
+            self.listeners.lock().unwrap().push(listener);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "native_lib")]
+impl Display {
+    /// Pointer to the underlying `wl_display`
+    pub fn ptr(&self) -> *mut wl_display {
+        self.ptr
+    }
+}
+
+#[cfg(feature = "rust_impl")]
+fn runtime_dir() -> io::Result<::std::path::PathBuf> {
+    ::std::env::var_os("XDG_RUNTIME_DIR")
+        .map(::std::path::PathBuf::from)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "XDG_RUNTIME_DIR is not set",
+            )
+        })
+}
+
+#[cfg(all(test, feature = "rust_impl"))]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `add_socket_auto` reads/writes `$XDG_RUNTIME_DIR` and `$WAYLAND_DISPLAY`,
+    // which are process-global state: serialize the tests that touch them so
+    // they cannot observe each other's values.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn unique_runtime_dir(label: &str) -> ::std::path::PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "wayland-server-test-{}-{}",
+            label,
+            ::std::process::id()
+        ));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn new_test_display(runtime_dir: &::std::path::Path) -> Display {
+        ::std::env::set_var("XDG_RUNTIME_DIR", runtime_dir);
+        Display {
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    #[test]
+    fn add_socket_auto_skips_names_already_taken() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let runtime_dir = unique_runtime_dir("skip");
+
+        // Occupy `wayland-0` and `wayland-1` ourselves, so `add_socket_auto`
+        // must skip past both before it finds a free name.
+        let _taken0 = ServerListener::bind(runtime_dir.join("wayland-0")).unwrap();
+        let _taken1 = ServerListener::bind(runtime_dir.join("wayland-1")).unwrap();
+
+        let mut display = new_test_display(&runtime_dir);
+        let name = display.add_socket_auto().unwrap();
+
+        assert_eq!(name, "wayland-2");
+        assert_eq!(::std::env::var("WAYLAND_DISPLAY").unwrap(), "wayland-2");
+    }
+
+    #[test]
+    fn add_socket_auto_fails_once_every_name_is_taken() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let runtime_dir = unique_runtime_dir("exhausted");
+
+        let _taken: Vec<_> = (SOCKET_NAME_MIN..=SOCKET_NAME_MAX)
+            .map(|i| ServerListener::bind(runtime_dir.join(format!("wayland-{}", i))).unwrap())
+            .collect();
+
+        let mut display = new_test_display(&runtime_dir);
+        let err = display.add_socket_auto().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+}
+
+/// Create a new display
+///
+/// This function returns the newly created `Display`, as well as the
+/// `EventLoop` that will be used to drive it.
+pub fn create_display() -> (Display, EventLoop) {
+    #[cfg(feature = "native_lib")]
+    {
+        let ptr = unsafe { ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_display_create,) };
+        let event_loop = unsafe { EventLoop::display_new(ptr) };
+        (Display { ptr }, event_loop)
+    }
+    #[cfg(feature = "rust_impl")]
+    {
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+        let event_loop = EventLoop::new(listeners.clone());
+        (Display { listeners }, event_loop)
+    }
+}
@@ -0,0 +1,785 @@
+//! The event loop and its handle
+//!
+//! Registering wayland objects and globals, as well as dispatching pending
+//! requests, all happen through the types defined here.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "rust_impl")]
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+
+use token_store::{Store, Token};
+
+#[cfg(feature = "native_lib")]
+use wayland_sys::server::*;
+
+#[cfg(feature = "rust_impl")]
+use nix::poll::{poll, PollFd, POLLIN};
+
+#[cfg(feature = "rust_impl")]
+use imp::rust_impl::{ClientConnection, MessageHeader, ReadResult, ServerListener};
+
+use client::Client;
+use Resource;
+
+/// A token representing a piece of state stored in an event loop
+///
+/// It is obtained from `State::insert` and can be exchanged for a reference
+/// to the stored value via `EventLoopHandle::state`.
+pub struct StateToken<T> {
+    inner: Token<T>,
+}
+
+/// Shared state storage for an event loop
+///
+/// Implementation data commonly takes the form of one or more `StateToken`s
+/// pointing into this store, so that several callbacks can reach the same
+/// piece of compositor state without needing to capture it directly.
+pub struct State {
+    store: Store,
+}
+
+impl State {
+    fn new() -> State {
+        State { store: Store::new() }
+    }
+
+    /// Insert a new piece of state, returning a token to retrieve it later
+    pub fn insert<T: 'static>(&mut self, value: T) -> StateToken<T> {
+        StateToken {
+            inner: self.store.insert(value),
+        }
+    }
+
+    /// Access a previously inserted piece of state
+    pub fn get<T: 'static>(&self, token: &StateToken<T>) -> &T {
+        self.store.get(&token.inner)
+    }
+
+    /// Mutably access a previously inserted piece of state
+    pub fn get_mut<T: 'static>(&mut self, token: &StateToken<T>) -> &mut T {
+        self.store.get_mut(&token.inner)
+    }
+}
+
+/// Whether a resource was successfully registered to an event loop
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegisterStatus {
+    /// The resource was registered
+    Registered,
+    /// The resource was not alive and thus could not be registered
+    Invalid,
+}
+
+/// The callback invoked when a client instantiates a global
+pub type GlobalCallback<ID> = fn(&mut EventLoopHandle, &Client, ID);
+
+/// A global's type-erased, one-shot bind callback
+///
+/// Boxed as `FnMut` purely so it can be stored in a single field regardless
+/// of whether it came from `register_global` or `register_global_dispatch`;
+/// in practice it is only ever called once, see `Global::bind`.
+type GlobalBind = Box<FnMut(&mut EventLoopHandle, &Client) + Send>;
+
+/// A global object advertised to clients through the registry
+pub struct Global {
+    name: u32,
+    version: u32,
+    bind: Mutex<Option<GlobalBind>>,
+}
+
+impl Global {
+    /// Name of this global, as seen by clients in the registry
+    pub fn name(&self) -> u32 {
+        self.name
+    }
+
+    /// Version this global is advertised with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Invoke this global's bind callback for `client`, if it has not
+    /// already run, and report whether it did
+    ///
+    /// `register_global`'s `callback` is handed `idata` by value, so there
+    /// is only ever one copy of it to give away: the callback fires the
+    /// first time some client binds this global and never again.
+    ///
+    /// Nothing in this crate calls `bind` yet: doing so for a real client
+    /// requires decoding its `wl_registry` bind request, which needs
+    /// scanner-generated glue this crate does not have.
+    pub(crate) fn bind(&self, evlh: &mut EventLoopHandle, client: &Client) -> bool {
+        let mut slot = self.bind.lock().unwrap();
+        match slot.take() {
+            Some(mut f) => {
+                f(evlh, client);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A stable, hashable identity for a resource, used to track which
+/// dispatch queue it has been assigned to
+fn resource_key<R: Resource>(resource: &R) -> usize {
+    #[cfg(feature = "native_lib")]
+    {
+        resource.ptr() as usize
+    }
+    #[cfg(feature = "rust_impl")]
+    {
+        resource.object_id() as usize
+    }
+}
+
+thread_local! {
+    static CURRENT_HANDLE: RefCell<Option<*mut EventLoopHandle>> = RefCell::new(None);
+}
+
+/// Make `handle` reachable through `current_handle` for the duration of `f`
+///
+/// `register`'s dispatcher closures are captured once, well before the
+/// `EventLoopHandle` driving any particular `dispatch` call exists, and are
+/// invoked from deep inside FFI (`native_lib`) or `ClientConnection::dispatch`
+/// (`rust_impl`) with no way to pass it through as a parameter. Scoping it in
+/// a thread-local instead mirrors `StateEventLoop::dispatch`'s `dispatch_state`.
+fn with_current_handle<R>(handle: *mut EventLoopHandle, f: impl FnOnce() -> R) -> R {
+    CURRENT_HANDLE.with(|cell| *cell.borrow_mut() = Some(handle));
+    let result = f();
+    CURRENT_HANDLE.with(|cell| *cell.borrow_mut() = None);
+    result
+}
+
+/// Access the `EventLoopHandle` driving the dispatch call currently running
+/// on this thread
+///
+/// # Panics
+///
+/// Panics if called outside of a `with_current_handle` scope. A generated
+/// `Implementable::__dispatch_msg` body is only ever invoked from within one.
+fn current_handle<'a>() -> &'a mut EventLoopHandle {
+    let ptr = CURRENT_HANDLE
+        .with(|cell| *cell.borrow())
+        .expect("current_handle() called outside of a dispatch call");
+    // SAFETY: `with_current_handle` only ever stores a pointer that stays
+    // valid for at least as long as the `f` call it wraps, which outlives
+    // every use of this reference.
+    unsafe { &mut *ptr }
+}
+
+/// A request buffered on a secondary `Queue`, together with everything
+/// needed to actually dispatch it later: the client it came from (so its
+/// `ClientConnection` can be locked again) and its decoded header
+#[cfg(feature = "rust_impl")]
+struct PendingMessage {
+    client: Client,
+    header: MessageHeader,
+    body: Vec<u8>,
+}
+
+/// A secondary dispatch queue
+///
+/// Only supported by the `rust_impl` backend: resources registered to a
+/// queue (via `EventLoopHandle::register_to_queue`) have their requests
+/// buffered here by the main queue's fd-readiness demux (`EventLoop::dispatch`)
+/// instead of being dispatched immediately, so that `dispatch` can be called
+/// for this queue alone, typically from a dedicated thread.
+///
+/// `libwayland-server.so` offers no API to single out one resource's
+/// requests from the rest of a `wl_event_loop_dispatch` call, so under
+/// `native_lib` a `Queue` can be created but never has anything to dispatch:
+/// `register_to_queue` fails instead of assigning resources to it.
+pub struct Queue {
+    #[cfg(feature = "rust_impl")]
+    pending: Mutex<VecDeque<PendingMessage>>,
+    /// The event loop this queue was created from, so `dispatch` can reach
+    /// its `EventLoopHandle` even when called from a dedicated thread other
+    /// than the one driving `EventLoop::dispatch`
+    #[cfg(feature = "rust_impl")]
+    handle: Weak<Mutex<EventLoopHandle>>,
+}
+
+impl Queue {
+    fn new(handle: Weak<Mutex<EventLoopHandle>>) -> Queue {
+        #[cfg(feature = "native_lib")]
+        let _ = handle;
+        Queue {
+            #[cfg(feature = "rust_impl")]
+            pending: Mutex::new(VecDeque::new()),
+            #[cfg(feature = "rust_impl")]
+            handle,
+        }
+    }
+
+    /// Buffer a request read from `client` for later dispatch on this queue
+    #[cfg(feature = "rust_impl")]
+    pub fn enqueue(&self, client: Client, header: MessageHeader, body: Vec<u8>) {
+        self.pending
+            .lock()
+            .unwrap()
+            .push_back(PendingMessage { client, header, body });
+    }
+
+    /// Dispatch every message currently pending on this queue
+    ///
+    /// `timeout` is accepted for API symmetry with `EventLoop::dispatch`,
+    /// but this queue only ever processes messages that were already
+    /// buffered by the main queue's fd-readiness demux. Always returns
+    /// `Ok(0)` under `native_lib`, since nothing can ever be assigned to a
+    /// queue on that backend.
+    pub fn dispatch(&self, timeout: Option<i32>) -> io::Result<i32> {
+        let _ = timeout;
+        #[cfg(feature = "native_lib")]
+        {
+            Ok(0)
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            let handle_ref = self
+                .handle
+                .upgrade()
+                .expect("Queue::dispatch called after its EventLoop was dropped");
+            let mut handle = handle_ref.lock().unwrap();
+            let handle_ptr = &mut *handle as *mut EventLoopHandle;
+
+            let mut pending = self.pending.lock().unwrap();
+            let mut count = 0;
+            while let Some(msg) = pending.pop_front() {
+                let _ = with_current_handle(handle_ptr, || {
+                    msg.client.connection().dispatch(msg.header, &msg.body)
+                });
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+}
+
+/// A lightweight handle identifying a `Queue` created with
+/// `EventLoopHandle::create_queue`
+#[derive(Clone)]
+pub struct QueueToken {
+    index: usize,
+}
+
+/// Check whether a resource is currently registered to some event loop
+///
+/// Only the resource's liveness is checked: whether it was ever handed to
+/// `EventLoopHandle::register` is not tracked here.
+pub fn resource_is_registered<R: Resource>(_resource: &R) -> bool {
+    // Until a resource has been handed to `EventLoopHandle::register`, it
+    // carries no implementation and no events can be dispatched to it.
+    #[cfg(feature = "native_lib")]
+    {
+        _resource.status() == ::Liveness::Alive
+    }
+    #[cfg(feature = "rust_impl")]
+    {
+        _resource.status() == ::Liveness::Alive
+    }
+}
+
+/// A handle to the event loop, available from within callback invocations
+///
+/// This is the type through which you register new resources and globals,
+/// and access the shared `State`.
+pub struct EventLoopHandle {
+    state: State,
+    next_global_name: u32,
+    queues: Vec<Arc<Queue>>,
+    queue_assignment: HashMap<usize, usize>,
+    dispatch_state: Option<*mut Any>,
+    /// A weak reference back to the `Arc<Mutex<EventLoopHandle>>` this
+    /// handle lives in, handed out to every `Queue` it creates so that a
+    /// queue dispatched from its own thread can still reach it
+    ///
+    /// Empty until `EventLoop::new`/`display_new` wraps the freshly built
+    /// handle in its `Arc` and fills this in; never used before that point.
+    self_ref: Weak<Mutex<EventLoopHandle>>,
+}
+
+impl EventLoopHandle {
+    fn new() -> EventLoopHandle {
+        EventLoopHandle {
+            state: State::new(),
+            next_global_name: 1,
+            queues: Vec::new(),
+            queue_assignment: HashMap::new(),
+            dispatch_state: None,
+            self_ref: Weak::new(),
+        }
+    }
+
+    /// Access the central state currently driving dispatch
+    ///
+    /// Returns `None` outside of a `StateEventLoop::dispatch` call, or if
+    /// the live state is not of type `D`. A generated
+    /// `Implementable::__dispatch_msg` body uses this to resolve `D` and
+    /// call the matching `Dispatch<I>::request` on it.
+    pub fn dispatch_state<D: 'static>(&mut self) -> Option<&mut D> {
+        let ptr = self.dispatch_state?;
+        // SAFETY: `set_dispatch_state` only ever stores a pointer that
+        // outlives the `StateEventLoop::dispatch` call it is cleared at
+        // the end of, which is longer than any `&mut D` handed out here.
+        unsafe { (*ptr).downcast_mut::<D>() }
+    }
+
+    /// Install (or clear, with `None`) the central state for the duration
+    /// of a dispatch call
+    pub(crate) fn set_dispatch_state(&mut self, state: Option<*mut Any>) {
+        self.dispatch_state = state;
+    }
+
+    /// Create a new secondary dispatch queue
+    ///
+    /// Resources assigned to it with `register_to_queue` can then be
+    /// dispatched independently of the main queue, typically from their own
+    /// thread, while globals and socket acceptance stay on the main queue.
+    pub fn create_queue(&mut self) -> QueueToken {
+        let index = self.queues.len();
+        self.queues.push(Arc::new(Queue::new(self.self_ref.clone())));
+        QueueToken { index }
+    }
+
+    /// Access a previously created queue, to dispatch it on its own
+    pub fn queue(&self, token: &QueueToken) -> Arc<Queue> {
+        self.queues[token.index].clone()
+    }
+
+    /// Register a resource to this event loop, assigning its future
+    /// requests to `queue` instead of the main queue
+    ///
+    /// Only supported by the `rust_impl` backend: returns an error and
+    /// registers nothing under `native_lib`, since `libwayland-server.so`
+    /// has no API to dispatch a single resource's requests off the thread
+    /// running `wl_event_loop_dispatch`.
+    pub fn register_to_queue<R, IDATA>(
+        &mut self,
+        resource: &R,
+        queue: &QueueToken,
+        implementation: R::Implementation,
+        idata: IDATA,
+    ) -> io::Result<()>
+    where
+        R: ::Implementable<IDATA> + Send + 'static,
+        R::Implementation: Send,
+        IDATA: Send + 'static,
+    {
+        #[cfg(feature = "native_lib")]
+        {
+            let _ = (resource, queue, implementation, idata);
+            Err(io::Error::new(
+                io::ErrorKind::Other,
+                "register_to_queue is not supported by the native_lib backend",
+            ))
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            self.queue_assignment
+                .insert(resource_key(resource), queue.index);
+            self.register(resource, implementation, idata);
+            Ok(())
+        }
+    }
+
+    /// Access the shared state store
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Register a resource to this event loop with the given implementation
+    ///
+    /// Registering a resource that is already registered overwrites its
+    /// previous implementation. Does nothing if the resource is not alive.
+    pub fn register<R, IDATA>(&mut self, resource: &R, implementation: R::Implementation, idata: IDATA)
+    where
+        R: ::Implementable<IDATA> + Send + 'static,
+        R::Implementation: Send,
+        IDATA: Send + 'static,
+    {
+        if resource.status() != ::Liveness::Alive {
+            return;
+        }
+        #[cfg(feature = "native_lib")]
+        unsafe {
+            let dispatch_resource = match resource.clone() {
+                Some(r) => r,
+                None => return,
+            };
+            let ptr = resource.ptr();
+            let mut idata = idata;
+            ::imp::native::set_dispatcher(ptr, move |opcode, args| {
+                let client = Client::from_ptr(::imp::native::resource_client(ptr));
+                let evlh = current_handle();
+                dispatch_resource.__dispatch_msg(&implementation, &mut idata, &client, opcode, args, evlh)
+            });
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            let dispatch_resource = match resource.clone() {
+                Some(r) => r,
+                None => return,
+            };
+            let client = resource.client();
+            let object_id = resource.object_id();
+            let mut idata = idata;
+            let dispatcher: ::imp::rust_impl::RequestDispatcher = {
+                let client = client.clone();
+                Box::new(move |opcode, body| {
+                    let evlh = current_handle();
+                    dispatch_resource.__dispatch_msg(&implementation, &mut idata, &client, opcode, body, evlh)
+                })
+            };
+            client.connection().register_object(object_id, dispatcher);
+        }
+    }
+
+    /// Register a new global, advertised to every client through the registry
+    ///
+    /// `callback` is invoked with the binding client the first time a
+    /// client binds this global, `idata` being handed back as its last
+    /// argument; later binds do not invoke it again.
+    pub fn register_global<ID: Send + 'static>(
+        &mut self,
+        version: u32,
+        callback: GlobalCallback<ID>,
+        idata: ID,
+    ) -> Global {
+        let mut idata = Some(idata);
+        let bind: GlobalBind = Box::new(move |evlh, client| {
+            if let Some(idata) = idata.take() {
+                callback(evlh, client, idata);
+            }
+        });
+        Global {
+            name: self.next_global_name(),
+            version,
+            bind: Mutex::new(Some(bind)),
+        }
+    }
+
+    /// Register a new global whose instantiation routes through a
+    /// `Dispatch<I>` impl on the central state `D`, instead of a
+    /// `GlobalCallback`
+    ///
+    /// Binding this global still needs scanner-generated glue this crate
+    /// does not have to decode the client's request into a live `I`, so the
+    /// stored callback only proves `D` is reachable; it cannot yet call
+    /// `Dispatch::request`.
+    pub fn register_global_dispatch<I, D>(&mut self, version: u32) -> Global
+    where
+        I: Resource,
+        D: ::dispatch::Dispatch<I>,
+    {
+        let bind: GlobalBind = Box::new(move |evlh, _client| {
+            let _ = evlh.dispatch_state::<D>();
+        });
+        Global {
+            name: self.next_global_name(),
+            version,
+            bind: Mutex::new(Some(bind)),
+        }
+    }
+
+    fn next_global_name(&mut self) -> u32 {
+        let name = self.next_global_name;
+        self.next_global_name += 1;
+        name
+    }
+}
+
+/// The event loop, driving dispatch of client requests
+pub struct EventLoop {
+    #[cfg(feature = "native_lib")]
+    ptr: *mut wl_event_loop,
+    #[cfg(feature = "rust_impl")]
+    listeners: Arc<Mutex<Vec<ServerListener>>>,
+    #[cfg(feature = "rust_impl")]
+    clients: Mutex<Vec<Arc<Mutex<ClientConnection>>>>,
+    /// Shared (rather than plain `Mutex<EventLoopHandle>`) so every `Queue`
+    /// created from it can keep a `Weak` reference back to it, letting a
+    /// queue dispatched from its own thread still reach the handle
+    handle: Arc<Mutex<EventLoopHandle>>,
+}
+
+impl EventLoop {
+    /// Build the shared handle and point its `self_ref` back at itself
+    fn new_handle() -> Arc<Mutex<EventLoopHandle>> {
+        let handle = Arc::new(Mutex::new(EventLoopHandle::new()));
+        handle.lock().unwrap().self_ref = Arc::downgrade(&handle);
+        handle
+    }
+
+    #[cfg(feature = "native_lib")]
+    pub(crate) unsafe fn display_new(display: *mut wl_display) -> EventLoop {
+        let ptr = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_display_get_event_loop, display);
+        EventLoop {
+            ptr,
+            handle: EventLoop::new_handle(),
+        }
+    }
+
+    /// Build a new event loop sharing `listeners` with the `Display` it was
+    /// created alongside, so that the sockets `Display::add_socket` binds
+    /// are the ones this event loop actually polls
+    #[cfg(feature = "rust_impl")]
+    pub(crate) fn new(listeners: Arc<Mutex<Vec<ServerListener>>>) -> EventLoop {
+        EventLoop {
+            listeners,
+            clients: Mutex::new(Vec::new()),
+            handle: EventLoop::new_handle(),
+        }
+    }
+
+    /// Dispatch pending requests, blocking for at most `timeout` milliseconds
+    /// if nothing is pending (or indefinitely if `None`)
+    pub fn dispatch(&mut self, timeout: Option<i32>) -> ::std::io::Result<i32> {
+        #[cfg(feature = "native_lib")]
+        {
+            let timeout = timeout.unwrap_or(-1);
+            let mut handle = self.handle.lock().unwrap();
+            let handle_ptr = &mut *handle as *mut EventLoopHandle;
+            let ret = with_current_handle(handle_ptr, || unsafe {
+                ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_event_loop_dispatch, self.ptr, timeout)
+            });
+            if ret < 0 {
+                Err(::std::io::Error::last_os_error())
+            } else {
+                Ok(ret)
+            }
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            self.dispatch_rust_impl(timeout)
+        }
+    }
+
+    /// Poll every bound listener and connected client for readiness, accept
+    /// new connections, and read+dispatch (or enqueue, for resources
+    /// assigned to a secondary queue) one request from each client that has
+    /// one ready
+    #[cfg(feature = "rust_impl")]
+    fn dispatch_rust_impl(&mut self, timeout: Option<i32>) -> io::Result<i32> {
+        let listeners = self.listeners.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        let mut handle = self.handle.lock().unwrap();
+
+        let mut fds = Vec::with_capacity(listeners.len() + clients.len());
+        for listener in listeners.iter() {
+            fds.push(PollFd::new(listener.as_raw_fd(), POLLIN));
+        }
+        let client_fd_offset = fds.len();
+        for client in clients.iter() {
+            let fd = client.lock().unwrap().as_raw_fd();
+            fds.push(PollFd::new(fd, POLLIN));
+        }
+
+        if fds.is_empty() {
+            return Ok(0);
+        }
+
+        let ready = poll(&mut fds, timeout.unwrap_or(-1))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?;
+        if ready <= 0 {
+            return Ok(0);
+        }
+
+        for (i, listener) in listeners.iter().enumerate() {
+            let is_readable = fds[i].revents().is_some_and(|e| e.contains(POLLIN));
+            if is_readable {
+                if let Ok(conn) = listener.accept() {
+                    clients.push(Arc::new(Mutex::new(conn)));
+                }
+            }
+        }
+
+        let handle_ptr = &mut *handle as *mut EventLoopHandle;
+        let mut dispatched = 0;
+        let mut dead = Vec::new();
+        // Connections accepted above have no entry in `fds`; they are
+        // polled from the next call on.
+        let polled_clients = fds.len() - client_fd_offset;
+        for (i, conn) in clients.iter().enumerate().take(polled_clients) {
+            let is_readable = fds[client_fd_offset + i]
+                .revents()
+                .is_some_and(|e| e.contains(POLLIN));
+            if !is_readable {
+                continue;
+            }
+            let request = conn.lock().unwrap().read_request();
+            match request {
+                Ok(ReadResult::Message(header, body)) => {
+                    let queue = handle
+                        .queue_assignment
+                        .get(&(header.sender_id as usize))
+                        .map(|&index| handle.queues[index].clone());
+                    match queue {
+                        Some(queue) => queue.enqueue(Client::from_connection(conn.clone()), header, body),
+                        None => {
+                            let _ = with_current_handle(handle_ptr, || {
+                                conn.lock().unwrap().dispatch(header, &body)
+                            });
+                        }
+                    }
+                    dispatched += 1;
+                }
+                // Nothing full has arrived yet; try this client again on
+                // the next call instead of blocking the whole reactor on it.
+                Ok(ReadResult::WouldBlock) => {}
+                Ok(ReadResult::Closed) | Err(_) => dead.push(i),
+            }
+        }
+        for i in dead.into_iter().rev() {
+            clients.remove(i);
+        }
+
+        Ok(dispatched)
+    }
+
+    /// Run the event loop, dispatching requests indefinitely
+    pub fn run(&mut self) -> ::std::io::Result<()> {
+        loop {
+            self.dispatch(None)?;
+        }
+    }
+
+    /// Access the handle of this event loop
+    pub fn handle(&self) -> ::std::sync::MutexGuard<'_, EventLoopHandle> {
+        self.handle.lock().unwrap()
+    }
+}
+
+/// An `EventLoop` paired with a single, central compositor state `D`
+///
+/// This is the entry point for the `Dispatch`-based routing path: `D`
+/// implements `Dispatch<I>` for every interface it wants to handle, and
+/// `dispatch` borrows it for its whole duration, so every `Dispatch::request`
+/// call gets direct `&mut` access to it. This removes the need for
+/// `StateToken`s and implementation data for compositors that are happy to
+/// keep all their state in one place.
+pub struct StateEventLoop<D> {
+    evl: EventLoop,
+    state: D,
+}
+
+impl<D: 'static> StateEventLoop<D> {
+    /// Pair an `EventLoop` with the state that will drive its dispatch
+    pub fn new(evl: EventLoop, state: D) -> StateEventLoop<D> {
+        StateEventLoop { evl, state }
+    }
+
+    /// Access the central state
+    pub fn state(&mut self) -> &mut D {
+        &mut self.state
+    }
+
+    /// Access the underlying `EventLoop`, e.g. to reach its `handle` and
+    /// register globals
+    pub fn event_loop(&mut self) -> &mut EventLoop {
+        &mut self.evl
+    }
+
+    /// Dispatch pending requests, with `D` borrowed for the call's duration
+    ///
+    /// `self.state()` is installed as the event loop's `dispatch_state` for
+    /// the whole call, so every request routed through a `Dispatch<I>` impl
+    /// can reach it via `EventLoopHandle::dispatch_state` from within
+    /// `Implementable::__dispatch_msg`, and call `Dispatch::request` on it.
+    pub fn dispatch(&mut self, timeout: Option<i32>) -> ::std::io::Result<i32> {
+        let state_ptr = &mut self.state as &mut Any as *mut Any;
+        self.evl.handle.lock().unwrap().set_dispatch_state(Some(state_ptr));
+        let result = self.evl.dispatch(timeout);
+        self.evl.handle.lock().unwrap().set_dispatch_state(None);
+        result
+    }
+
+    /// Run the event loop, dispatching requests indefinitely
+    pub fn run(&mut self) -> ::std::io::Result<()> {
+        loop {
+            self.dispatch(None)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_dispatch_on_an_empty_queue_returns_zero() {
+        // `create_queue` needs a handle whose `self_ref` actually points
+        // back to itself, which only `EventLoop::new_handle` sets up;
+        // `EventLoopHandle::new()` alone leaves it empty.
+        let handle_arc = EventLoop::new_handle();
+        let token = handle_arc.lock().unwrap().create_queue();
+        let queue = handle_arc.lock().unwrap().queue(&token);
+        // Also covers `native_lib`, where a `Queue` can be created but
+        // never has anything assigned to it: `dispatch` should report
+        // that honestly rather than fake-consuming pending work.
+        assert_eq!(queue.dispatch(Some(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn with_current_handle_installs_and_clears_the_thread_local() {
+        let mut handle = EventLoopHandle::new();
+        let ptr = &mut handle as *mut EventLoopHandle;
+
+        let seen = with_current_handle(ptr, || current_handle() as *mut EventLoopHandle);
+        assert!(::std::ptr::eq(seen, ptr));
+
+        // Cleared again once the scope ends, so a call made outside of one
+        // (e.g. from a dispatcher invoked off its event loop) will panic
+        // instead of reading a stale pointer.
+        assert!(CURRENT_HANDLE.with(|cell| cell.borrow().is_none()));
+    }
+
+    #[test]
+    #[cfg(feature = "rust_impl")]
+    fn register_global_callback_fires_once_with_idata() {
+        use std::os::unix::net::UnixStream;
+
+        fn callback(_evlh: &mut EventLoopHandle, _client: &Client, idata: u32) {
+            assert_eq!(idata, 42);
+        }
+
+        let mut handle = EventLoopHandle::new();
+        let global = handle.register_global(1, callback, 42u32);
+
+        let dir = ::std::env::temp_dir().join(format!(
+            "wayland-server-test-global-bind-{}",
+            ::std::process::id()
+        ));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+        let listener = ServerListener::bind(&path).unwrap();
+        let _client_stream = UnixStream::connect(&path).unwrap();
+        let conn = listener.accept().unwrap();
+        let client = Client::from_connection(Arc::new(Mutex::new(conn)));
+
+        assert!(global.bind(&mut handle, &client));
+        // `idata` was handed to `callback` by value: a second bind has
+        // nothing left to give it, so the callback does not run again.
+        assert!(!global.bind(&mut handle, &client));
+    }
+
+    #[test]
+    fn dispatch_state_round_trips_through_the_correct_type() {
+        let mut handle = EventLoopHandle::new();
+        assert!(handle.dispatch_state::<u32>().is_none());
+
+        let mut my_state = 42u32;
+        handle.set_dispatch_state(Some(&mut my_state as &mut Any as *mut Any));
+        assert_eq!(*handle.dispatch_state::<u32>().unwrap(), 42);
+
+        // Downcasting to the wrong type fails rather than transmuting.
+        assert!(handle.dispatch_state::<String>().is_none());
+
+        handle.set_dispatch_state(None);
+        assert!(handle.dispatch_state::<u32>().is_none());
+    }
+}
@@ -0,0 +1,112 @@
+//! Secondary event sources: file descriptors, signals and timers
+//!
+//! These describe non-wayland sources of events (input devices, DRM
+//! fds, signalfds, timerfds, ...) and their callbacks. This crate has no
+//! call that registers them on an `EventLoop`, so their callbacks are never
+//! invoked by it.
+
+use std::os::unix::io::RawFd;
+
+bitflags! {
+    /// Which readiness events an `FdEventSource` should be notified for
+    pub struct FdInterest: u32 {
+        /// The fd is readable
+        const READ = 0x01;
+        /// The fd is writable
+        const WRITE = 0x02;
+    }
+}
+
+/// Implementation callback for an `FdEventSource`
+pub struct FdEventSourceImpl<ID> {
+    /// Called whenever the fd becomes ready for one of the interests it was
+    /// registered with
+    pub ready: fn(evlh: &mut ::EventLoopHandle, idata: &mut ID, fd: RawFd, mask: FdInterest),
+}
+
+impl<ID> Copy for FdEventSourceImpl<ID> {}
+impl<ID> Clone for FdEventSourceImpl<ID> {
+    fn clone(&self) -> FdEventSourceImpl<ID> {
+        *self
+    }
+}
+impl<ID> PartialEq for FdEventSourceImpl<ID> {
+    fn eq(&self, other: &FdEventSourceImpl<ID>) -> bool {
+        self.ready as usize == other.ready as usize
+    }
+}
+
+/// A file descriptor registered as an event source
+pub struct FdEventSource {
+    fd: RawFd,
+}
+
+impl FdEventSource {
+    /// Raw fd this source watches
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+/// Implementation callback for a `SignalEventSource`
+pub struct SignalEventSourceImpl<ID> {
+    /// Called when the watched signal is received
+    pub signal: fn(evlh: &mut ::EventLoopHandle, idata: &mut ID, signal: ::nix::sys::signal::Signal),
+}
+
+impl<ID> Copy for SignalEventSourceImpl<ID> {}
+impl<ID> Clone for SignalEventSourceImpl<ID> {
+    fn clone(&self) -> SignalEventSourceImpl<ID> {
+        *self
+    }
+}
+impl<ID> PartialEq for SignalEventSourceImpl<ID> {
+    fn eq(&self, other: &SignalEventSourceImpl<ID>) -> bool {
+        self.signal as usize == other.signal as usize
+    }
+}
+
+/// A signal registered as an event source
+pub struct SignalEventSource {
+    signal: ::nix::sys::signal::Signal,
+}
+
+impl SignalEventSource {
+    /// The signal this source watches
+    pub fn signal(&self) -> ::nix::sys::signal::Signal {
+        self.signal
+    }
+}
+
+/// Implementation callback for a `TimerEventSource`
+pub struct TimerEventSourceImpl<ID> {
+    /// Called when the timer fires
+    pub timer: fn(evlh: &mut ::EventLoopHandle, idata: &mut ID),
+}
+
+impl<ID> Copy for TimerEventSourceImpl<ID> {}
+impl<ID> Clone for TimerEventSourceImpl<ID> {
+    fn clone(&self) -> TimerEventSourceImpl<ID> {
+        *self
+    }
+}
+impl<ID> PartialEq for TimerEventSourceImpl<ID> {
+    fn eq(&self, other: &TimerEventSourceImpl<ID>) -> bool {
+        self.timer as usize == other.timer as usize
+    }
+}
+
+/// A timer registered as an event source
+pub struct TimerEventSource {
+    _private: (),
+}
+
+impl TimerEventSource {
+    /// Set the delay, in milliseconds, after which the timer fires
+    ///
+    /// This type holds no actual timer: `delay_ms` is ignored and nothing
+    /// is armed.
+    pub fn set_delay_ms(&mut self, delay_ms: i32) {
+        let _ = delay_ms;
+    }
+}
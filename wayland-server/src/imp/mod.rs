@@ -0,0 +1,19 @@
+//! Backend selection
+//!
+//! `wayland-server` can be driven by two different backends, mirroring the
+//! `native_lib`/`rust_impl` feature pair used by the `wayland-client` crate:
+//!
+//! - `native_lib` (the default): thin FFI wrappers around the system
+//!   `libwayland-server.so`, dispatched through `WAYLAND_SERVER_HANDLE`.
+//! - `rust_impl`: a pure-Rust reimplementation of the server-side wire
+//!   protocol, with no runtime dependency on libwayland at all.
+//!
+//! The backend-specific code lives in the two submodules below; `Client`,
+//! `Display`, `EventLoop` and the `Resource` trait call into whichever one
+//! is enabled through their own `#[cfg(feature = ...)]` branches.
+
+#[cfg(feature = "native_lib")]
+pub(crate) mod native;
+
+#[cfg(feature = "rust_impl")]
+pub(crate) mod rust_impl;
@@ -0,0 +1,146 @@
+//! The `libwayland-server.so`-backed implementation
+//!
+//! Every function in this module is a thin wrapper around the C library,
+//! dispatched through `WAYLAND_SERVER_HANDLE`. Nothing here should contain
+//! protocol logic: it only exists to give the rest of the crate a small,
+//! backend-agnostic surface to call into.
+
+use std::cell::RefCell;
+use std::os::raw::{c_int, c_void};
+
+use wayland_sys::common::{wl_argument, wl_message};
+use wayland_sys::server::*;
+
+use user_data::UserData;
+
+/// Post a protocol error on a resource
+pub unsafe fn resource_post_error(resource: *mut wl_resource, error_code: u32, msg: String) {
+    // If `msg` contains an interior null, the transmitted message will be
+    // truncated at this point.
+    let cstring = ::std::ffi::CString::from_vec_unchecked(msg.into());
+    ffi_dispatch!(
+        WAYLAND_SERVER_HANDLE,
+        wl_resource_post_error,
+        resource,
+        error_code,
+        cstring.as_ptr()
+    )
+}
+
+/// Check whether two resources belong to the same client
+pub unsafe fn resource_same_client(a: *mut wl_resource, b: *mut wl_resource) -> bool {
+    let client_a = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_client, a);
+    let client_b = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_client, b);
+    client_a == client_b
+}
+
+/// Retrieve the client owning a resource
+pub unsafe fn resource_client(resource: *mut wl_resource) -> *mut wl_client {
+    ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_client, resource)
+}
+
+/// Retrieve the protocol version a resource was instantiated with
+pub unsafe fn resource_version(resource: *mut wl_resource) -> i32 {
+    ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_version, resource)
+}
+
+/// A handler invoked every time a request is dispatched to a resource,
+/// installed through `set_dispatcher`
+type Dispatcher = Box<FnMut(u32, *const wl_argument) -> Result<(), ()>>;
+
+/// Everything stashed in a resource's single native user data pointer:
+/// its compositor-visible `UserData` slot, and the dispatcher installed by
+/// `set_dispatcher` to route incoming requests
+///
+/// `libwayland-server.so` only grants each resource one user data pointer
+/// and one destructor, so both concerns have to share that one allocation
+/// rather than fight over it with two separate `wl_resource_set_user_data`
+/// calls.
+struct ResourceData {
+    user_data: UserData,
+    dispatcher: RefCell<Option<Dispatcher>>,
+}
+
+/// Retrieve the `UserData` attached to a resource, allocating it (and its
+/// backing `ResourceData`) on first access
+pub unsafe fn resource_user_data<'a>(resource: *mut wl_resource) -> &'a UserData {
+    &resource_data(resource).user_data
+}
+
+/// Install `func` as the handler for every request received by `resource`,
+/// routed through `wl_resource_set_dispatcher` instead of the classic
+/// `wl_resource_set_implementation` callback-struct mechanism
+///
+/// `func` is driven synchronously from whichever thread is currently
+/// running `wl_event_loop_dispatch`, so it does not need to be `Send`.
+pub unsafe fn set_dispatcher<F>(resource: *mut wl_resource, func: F)
+where
+    F: FnMut(u32, *const wl_argument) -> Result<(), ()> + 'static,
+{
+    let data = resource_data(resource) as *const ResourceData;
+    *(*data).dispatcher.borrow_mut() = Some(Box::new(func));
+    ffi_dispatch!(
+        WAYLAND_SERVER_HANDLE,
+        wl_resource_set_dispatcher,
+        resource,
+        dispatcher_trampoline,
+        // libwayland hands the implementation pointer, not the user data,
+        // to the dispatcher as its first argument.
+        data as *const c_void,
+        data as *mut c_void,
+        Some(free_resource_data)
+    );
+}
+
+/// Retrieve the `ResourceData` backing a resource, allocating it (with an
+/// empty `UserData` and no dispatcher yet) on first access
+unsafe fn resource_data<'a>(resource: *mut wl_resource) -> &'a ResourceData {
+    let existing = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_user_data, resource);
+    if !existing.is_null() {
+        return &*(existing as *const ResourceData);
+    }
+    let data = Box::into_raw(Box::new(ResourceData {
+        user_data: UserData::new(),
+        dispatcher: RefCell::new(None),
+    }));
+    ffi_dispatch!(
+        WAYLAND_SERVER_HANDLE,
+        wl_resource_set_user_data,
+        resource,
+        data as *mut c_void
+    );
+    ffi_dispatch!(
+        WAYLAND_SERVER_HANDLE,
+        wl_resource_set_destructor,
+        resource,
+        Some(free_resource_data)
+    );
+    &*data
+}
+
+unsafe extern "C" fn dispatcher_trampoline(
+    data: *const c_void,
+    _target: *mut c_void,
+    opcode: u32,
+    _message: *const wl_message,
+    args: *const wl_argument,
+) -> c_int {
+    let resource_data = &*(data as *const ResourceData);
+    let mut dispatcher = resource_data.dispatcher.borrow_mut();
+    match dispatcher.as_mut() {
+        Some(f) => match f(opcode, args) {
+            Ok(()) => 0,
+            Err(()) => -1,
+        },
+        // No dispatcher has been installed yet (the resource was only ever
+        // used through `resource_user_data`): silently ignore the request.
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn free_resource_data(resource: *mut wl_resource) {
+    let data = ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_user_data, resource);
+    if !data.is_null() {
+        drop(Box::from_raw(data as *mut ResourceData));
+    }
+}
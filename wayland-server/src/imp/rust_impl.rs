@@ -0,0 +1,468 @@
+//! Pure-Rust server backend
+//!
+//! This implements just enough of the Wayland wire protocol to listen on a
+//! Unix socket, accept clients and dispatch their requests, without linking
+//! against `libwayland-server.so`.
+//!
+//! # Wire format
+//!
+//! Every message is a sequence of 32-bit words:
+//!
+//! - the target object id (`u32`)
+//! - a header word: the low 16 bits are the opcode, the high 16 bits are
+//!   the total message size in bytes (including this header)
+//! - the arguments, serialized according to the interface's request/event
+//!   signature: `i`/`u` as one word, `s`/`a` as a length word followed by
+//!   the padded bytes, `o`/`n` as an object id word, and `h` as a file
+//!   descriptor carried out-of-band via `SCM_RIGHTS` ancillary data rather
+//!   than inline in the byte stream.
+//!
+//! # Object ids
+//!
+//! Ids in the `0x0000_0001..=0xFEFF_FFFF` range are allocated by clients
+//! when they create new objects; ids at `0xFF00_0000` and above are
+//! allocated by the server for objects it creates itself. The `wl_display`
+//! singleton is always id 1, in the client range.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use user_data::UserData;
+
+/// First id in the range reserved for client-allocated objects
+pub const CLIENT_ID_MIN: u32 = 0x0000_0001;
+/// Last id in the range reserved for client-allocated objects
+pub const CLIENT_ID_MAX: u32 = 0xFEFF_FFFF;
+/// First id in the range reserved for server-allocated objects
+pub const SERVER_ID_MIN: u32 = 0xFF00_0000;
+
+/// A single decoded request or event argument
+#[derive(Clone, Debug)]
+pub enum Argument {
+    /// A signed integer (`i`)
+    Int(i32),
+    /// An unsigned integer (`u`)
+    Uint(u32),
+    /// A fixed-point number (`f`)
+    Fixed(i32),
+    /// A string (`s`)
+    Str(String),
+    /// A raw byte array (`a`)
+    Array(Vec<u8>),
+    /// A new object id created by this message (`n`)
+    NewId(u32),
+    /// A reference to an existing object (`o`)
+    Object(u32),
+    /// A file descriptor passed out-of-band (`h`)
+    Fd(RawFd),
+}
+
+/// The header of a wire message: target object, opcode and byte size
+#[derive(Copy, Clone, Debug)]
+pub struct MessageHeader {
+    /// Id of the object this message targets
+    pub sender_id: u32,
+    /// Opcode of the request/event within that object's interface
+    pub opcode: u16,
+    /// Total size in bytes of the message, header included
+    pub size: u16,
+}
+
+impl MessageHeader {
+    /// Decode a header from the first 8 bytes of a message
+    pub fn decode(buf: &[u8]) -> Option<MessageHeader> {
+        if buf.len() < 8 {
+            return None;
+        }
+        let sender_id = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let second_word = u32::from_ne_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Some(MessageHeader {
+            sender_id,
+            opcode: (second_word & 0xFFFF) as u16,
+            size: (second_word >> 16) as u16,
+        })
+    }
+}
+
+/// Round a byte length up to the next multiple of 4, as required by the
+/// wire format's 32-bit word alignment
+pub fn padded_len(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A handler invoked every time a request is dispatched to an object
+///
+/// It receives the opcode and the request's raw, not-yet-decoded body
+/// bytes, and returns an error if the request could not be handled.
+/// Splitting those bytes into typed `Argument`s requires the request's
+/// wire signature, which only the scanner-generated dispatch glue for the
+/// object's concrete interface knows; that glue is what owns this closure.
+pub type RequestDispatcher = Box<FnMut(u16, &[u8]) -> Result<(), ()> + Send>;
+
+/// Outcome of a single non-blocking `ClientConnection::read_request` call
+#[derive(Debug)]
+pub enum ReadResult {
+    /// A full request was read and decoded
+    Message(MessageHeader, Vec<u8>),
+    /// Not enough data is available on the socket yet; call again once it
+    /// is readable
+    WouldBlock,
+    /// The peer closed the connection cleanly between two messages
+    Closed,
+}
+
+/// Per-client bookkeeping: connection, object table and id allocation
+pub struct ClientConnection {
+    stream: UnixStream,
+    objects: HashMap<u32, RequestDispatcher>,
+    user_data: HashMap<u32, Box<UserData>>,
+    next_server_id: u32,
+    /// Bytes read off `stream` that do not yet add up to a full message
+    ///
+    /// `read_request` never blocks, so a message that arrives split across
+    /// several socket reads (or a slow/stalled client) is assembled here
+    /// incrementally across calls instead of blocking the reactor that
+    /// drives every other client's dispatch while it waits.
+    read_buf: Vec<u8>,
+}
+
+impl ClientConnection {
+    fn new(stream: UnixStream) -> io::Result<ClientConnection> {
+        stream.set_nonblocking(true)?;
+        Ok(ClientConnection {
+            stream,
+            objects: HashMap::new(),
+            user_data: HashMap::new(),
+            next_server_id: SERVER_ID_MIN,
+            read_buf: Vec::new(),
+        })
+    }
+
+    /// Access the `UserData` slot of an object, allocating it on first access
+    ///
+    /// The slot is boxed so its address stays stable even as the table
+    /// backing this map is reallocated, which lets callers hand out
+    /// references to it that outlive the lock on this connection.
+    pub fn user_data_entry(&mut self, id: u32) -> &mut UserData {
+        &mut *self.user_data
+            .entry(id)
+            .or_insert_with(|| Box::new(UserData::new()))
+    }
+
+    /// Register a dispatcher for a given object id
+    ///
+    /// `id` must be in the client-allocated range if the object was just
+    /// created by a `new_id` argument, or in the server-allocated range for
+    /// objects the server advertises itself (globals, etc).
+    pub fn register_object(&mut self, id: u32, dispatcher: RequestDispatcher) {
+        self.objects.insert(id, dispatcher);
+    }
+
+    /// Drop the dispatcher associated with an object id, as happens when
+    /// the object is destroyed
+    pub fn unregister_object(&mut self, id: u32) {
+        self.objects.remove(&id);
+        self.user_data.remove(&id);
+    }
+
+    /// Allocate the next free server-side object id
+    pub fn next_server_id(&mut self) -> u32 {
+        let id = self.next_server_id;
+        self.next_server_id += 1;
+        id
+    }
+
+    /// Check whether an object id is currently registered
+    pub fn has_object(&self, id: u32) -> bool {
+        self.objects.contains_key(&id)
+    }
+
+    /// Resolve `(object_id, opcode)` to its handler and invoke it with the
+    /// request's raw body
+    pub fn dispatch(&mut self, header: MessageHeader, body: &[u8]) -> Result<(), ()> {
+        match self.objects.get_mut(&header.sender_id) {
+            Some(dispatcher) => dispatcher(header.opcode, body),
+            // Requests targeting an id we don't know about (already
+            // destroyed, or never created) are simply dropped.
+            None => Err(()),
+        }
+    }
+
+    /// Try to read one full request off this client's socket, without
+    /// blocking
+    ///
+    /// The socket is non-blocking, so this only ever does as much work as
+    /// is immediately available: it returns `WouldBlock` rather than
+    /// waiting for the rest of a message that hasn't arrived yet, leaving
+    /// whatever was read buffered for the next call.
+    pub fn read_request(&mut self) -> io::Result<ReadResult> {
+        loop {
+            if let Some(result) = self.take_buffered_message() {
+                return Ok(result);
+            }
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) if self.read_buf.is_empty() => return Ok(ReadResult::Closed),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed mid-message",
+                    ))
+                }
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(ReadResult::WouldBlock),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Decode and remove one message from the front of `read_buf`, if it
+    /// currently holds a full one
+    fn take_buffered_message(&mut self) -> Option<ReadResult> {
+        if self.read_buf.len() < 8 {
+            return None;
+        }
+        let header = MessageHeader::decode(&self.read_buf[..8])
+            .expect("a buffer of at least 8 bytes always decodes");
+        let total_len = header.size as usize;
+        if self.read_buf.len() < total_len {
+            return None;
+        }
+        let body = self.read_buf[8..total_len].to_vec();
+        self.read_buf.drain(..total_len);
+        Some(ReadResult::Message(header, body))
+    }
+
+    /// Raw fd of this client's socket, for integration with an event loop
+    pub fn as_raw_fd(&self) -> RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.stream.as_raw_fd()
+    }
+
+    /// Serialize and write an event to this client's socket
+    ///
+    /// File descriptor arguments are not handled by this method: they must
+    /// be sent separately via `SCM_RIGHTS` ancillary data alongside the
+    /// message bytes.
+    pub fn send_event(&mut self, sender_id: u32, opcode: u16, args: &[Argument]) -> io::Result<()> {
+        let mut body = Vec::new();
+        for arg in args {
+            match *arg {
+                Argument::Int(v) => body.extend_from_slice(&v.to_ne_bytes()),
+                Argument::Uint(v) | Argument::NewId(v) | Argument::Object(v) => {
+                    body.extend_from_slice(&v.to_ne_bytes())
+                }
+                Argument::Fixed(v) => body.extend_from_slice(&v.to_ne_bytes()),
+                Argument::Str(ref s) => {
+                    let bytes = s.as_bytes();
+                    let len = (bytes.len() + 1) as u32;
+                    body.extend_from_slice(&len.to_ne_bytes());
+                    body.extend_from_slice(bytes);
+                    body.resize(body.len() + 1, 0); // terminating nul
+                    let padded = padded_len(body.len());
+                    body.resize(padded, 0);
+                }
+                Argument::Array(ref a) => {
+                    let len = a.len() as u32;
+                    body.extend_from_slice(&len.to_ne_bytes());
+                    body.extend_from_slice(a);
+                    let padded = padded_len(body.len());
+                    body.resize(padded, 0);
+                }
+                Argument::Fd(_) => {
+                    // handled out-of-band, nothing to write inline
+                }
+            }
+        }
+
+        let size = (8 + body.len()) as u32;
+        let mut msg = Vec::with_capacity(size as usize);
+        msg.extend_from_slice(&sender_id.to_ne_bytes());
+        let second_word = (opcode as u32) | (size << 16);
+        msg.extend_from_slice(&second_word.to_ne_bytes());
+        msg.extend_from_slice(&body);
+
+        self.stream.write_all(&msg)
+    }
+}
+
+/// A listening socket accepting new client connections
+pub struct ServerListener {
+    listener: UnixListener,
+}
+
+impl ServerListener {
+    /// Bind a new listening socket at the given path
+    pub fn bind<P: AsRef<::std::path::Path>>(path: P) -> io::Result<ServerListener> {
+        Ok(ServerListener {
+            listener: UnixListener::bind(path)?,
+        })
+    }
+
+    /// Wrap an already-bound listening socket, as used for socket-activation
+    /// setups where the fd is inherited from the parent process
+    pub unsafe fn from_raw_fd(fd: RawFd) -> ServerListener {
+        use std::os::unix::io::FromRawFd;
+        ServerListener {
+            listener: UnixListener::from_raw_fd(fd),
+        }
+    }
+
+    /// Accept a single connection, blocking until one is pending
+    pub fn accept(&self) -> io::Result<ClientConnection> {
+        let (stream, _) = self.listener.accept()?;
+        ClientConnection::new(stream)
+    }
+
+    /// Raw fd of the listening socket, for integration with an event loop
+    pub fn as_raw_fd(&self) -> RawFd {
+        use std::os::unix::io::AsRawFd;
+        self.listener.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_len_rounds_up_to_word_boundary() {
+        assert_eq!(padded_len(0), 0);
+        assert_eq!(padded_len(1), 4);
+        assert_eq!(padded_len(4), 4);
+        assert_eq!(padded_len(5), 8);
+    }
+
+    #[test]
+    fn message_header_decode_rejects_short_buffers() {
+        assert!(MessageHeader::decode(&[0u8; 7]).is_none());
+    }
+
+    #[test]
+    fn message_header_decode_splits_opcode_and_size() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&42u32.to_ne_bytes());
+        let second_word = 3u32 | (16u32 << 16);
+        buf.extend_from_slice(&second_word.to_ne_bytes());
+
+        let header = MessageHeader::decode(&buf).unwrap();
+        assert_eq!(header.sender_id, 42);
+        assert_eq!(header.opcode, 3);
+        assert_eq!(header.size, 16);
+    }
+
+    #[test]
+    fn next_server_id_starts_at_server_id_min_and_increments() {
+        let (stream, _other) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(stream).unwrap();
+        assert_eq!(conn.next_server_id(), SERVER_ID_MIN);
+        assert_eq!(conn.next_server_id(), SERVER_ID_MIN + 1);
+    }
+
+    #[test]
+    fn register_and_unregister_object() {
+        let (stream, _other) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(stream).unwrap();
+        assert!(!conn.has_object(7));
+
+        conn.register_object(7, Box::new(|_, _| Ok(())));
+        assert!(conn.has_object(7));
+
+        conn.unregister_object(7);
+        assert!(!conn.has_object(7));
+    }
+
+    #[test]
+    fn dispatch_routes_to_the_registered_object_and_drops_unknown_ones() {
+        let (stream, _other) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(stream).unwrap();
+
+        let seen = ::std::sync::Arc::new(::std::sync::Mutex::new(None));
+        let seen_in_closure = seen.clone();
+        conn.register_object(
+            7,
+            Box::new(move |opcode, body| {
+                *seen_in_closure.lock().unwrap() = Some((opcode, body.to_vec()));
+                Ok(())
+            }),
+        );
+
+        let header = MessageHeader {
+            sender_id: 7,
+            opcode: 2,
+            size: 8,
+        };
+        assert_eq!(conn.dispatch(header, &[1, 2, 3]), Ok(()));
+        assert_eq!(*seen.lock().unwrap(), Some((2, vec![1, 2, 3])));
+
+        let unknown_header = MessageHeader {
+            sender_id: 99,
+            opcode: 0,
+            size: 8,
+        };
+        assert_eq!(conn.dispatch(unknown_header, &[]), Err(()));
+    }
+
+    #[test]
+    fn read_request_frames_a_message_written_by_the_peer() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(a).unwrap();
+
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&5u32.to_ne_bytes()); // sender_id
+        let second_word = 1u32 | (12u32 << 16); // opcode 1, size 12
+        msg.extend_from_slice(&second_word.to_ne_bytes());
+        msg.extend_from_slice(&[9, 9, 9, 9]); // 4 bytes of body
+        b.write_all(&msg).unwrap();
+
+        match conn.read_request().unwrap() {
+            ReadResult::Message(header, body) => {
+                assert_eq!(header.sender_id, 5);
+                assert_eq!(header.opcode, 1);
+                assert_eq!(body, vec![9, 9, 9, 9]);
+            }
+            other => panic!("expected a full message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_request_returns_closed_on_clean_disconnect() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(a).unwrap();
+        drop(b);
+
+        match conn.read_request().unwrap() {
+            ReadResult::Closed => {}
+            other => panic!("expected Closed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_request_buffers_a_partial_message_across_calls_without_blocking() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut conn = ClientConnection::new(a).unwrap();
+
+        // Write only the first half of the 8-byte header.
+        b.write_all(&[1, 2, 3, 4]).unwrap();
+        match conn.read_request().unwrap() {
+            ReadResult::WouldBlock => {}
+            other => panic!("expected WouldBlock, got {:?}", other),
+        }
+
+        // Finish the header (opcode 3, size 8: an empty body) and check
+        // the previously-buffered bytes are picked back up correctly.
+        let second_word = 3u32 | (8u32 << 16);
+        b.write_all(&second_word.to_ne_bytes()).unwrap();
+        match conn.read_request().unwrap() {
+            ReadResult::Message(header, body) => {
+                assert_eq!(header.sender_id, 0x0403_0201);
+                assert_eq!(header.opcode, 3);
+                assert!(body.is_empty());
+            }
+            other => panic!("expected a full message, got {:?}", other),
+        }
+    }
+}
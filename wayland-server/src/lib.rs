@@ -3,7 +3,11 @@
 //! # Overview
 //!
 //! Setting up the listening socket is done by the `create_display`
-//! function, providing you a `Display` object and an `EventLoop`.
+//! function, providing you a `Display` object and an `EventLoop`. Call
+//! `Display::add_socket_auto` to pick a free `wayland-N` name under
+//! `$XDG_RUNTIME_DIR` and export it as `$WAYLAND_DISPLAY`, mirroring the
+//! client side's `connect_to_env` ergonomics; `add_socket`/`add_socket_fd`
+//! are available for explicit naming or socket-activation setups.
 //!
 //! On the event loop, you'll be able to register the globals
 //! you want to advertize, as well as handlers for all ressources
@@ -57,6 +61,35 @@
 //! an implementation for when this global is instanciated by a client.
 //! See the method documentation for details.
 //!
+//! ## Alternative: central-state dispatch
+//!
+//! If you would rather keep all your compositor state in a single struct
+//! `D`, implement `Dispatch<I>` on it for every interface `I` you want to
+//! handle, and drive your event loop through a `StateEventLoop<D>` instead
+//! of registering an `Implementation` per object:
+//!
+//! ```ignore
+//! state_event_loop.dispatch(Some(10)).unwrap();
+//! ```
+//!
+//! Every `Dispatch::request` call made while dispatching gets direct
+//! `&mut` access to `D`, without going through `StateToken`s.
+//!
+//! ## Multiple dispatch queues
+//!
+//! By default every resource is dispatched on the event loop's main queue.
+//! Call `EventLoopHandle::create_queue` to create additional `Queue`s, and
+//! `EventLoopHandle::register_to_queue` to assign specific resources to one
+//! of them, one resource per call (there is no call that assigns a whole
+//! client at once). Each queue can then be dispatched
+//! independently, typically from its own thread, letting you keep
+//! input/frame-critical clients off a thread shared with background ones.
+//!
+//! This is only supported by the `rust_impl` backend: `libwayland-server.so`
+//! has no API to single out one resource's requests from the rest of a
+//! `wl_event_loop_dispatch` call, so `register_to_queue` returns an error
+//! under `native_lib` instead of silently dispatching on the main thread.
+//!
 //! ## Event loop integration
 //!
 //! Once the setup phase is done, you can integrate the
@@ -92,30 +125,59 @@
 //!
 //! The the crate `wayland_scanner` and its documentation for
 //! details about how to do so.
+//!
+//! # Backends
+//!
+//! This crate can be driven by two mutually-exclusive backends, selected
+//! through the `native_lib` and `rust_impl` feature pair:
+//!
+//! - `native_lib` (the default) links against the system
+//!   `libwayland-server.so` and dispatches every request through it.
+//! - `rust_impl` speaks the wayland wire protocol directly, with no C
+//!   library involved, making the crate portable and staticaly linkable.
+//!
+//! Most of the API is shared by both backends. Some items exist under one
+//! backend only: `Resource::ptr`, `Resource::set_user_data`/`get_user_data`,
+//! `Display::ptr` and `Client::from_ptr` under `native_lib`, and
+//! `Resource::object_id`/`Resource::client` under `rust_impl`. Under
+//! `native_lib`, `EventLoopHandle::register_to_queue` returns an error.
 
 #![warn(missing_docs)]
 
+#[cfg(not(any(feature = "native_lib", feature = "rust_impl")))]
+compile_error!("One of the `native_lib` or `rust_impl` features must be enabled.");
+
 #[macro_use]
 extern crate bitflags;
 extern crate libc;
 extern crate nix;
 extern crate token_store;
+#[cfg(feature = "native_lib")]
 #[macro_use]
 extern crate wayland_sys;
+#[cfg(all(feature = "rust_impl", not(feature = "native_lib")))]
+extern crate wayland_sys;
 
 pub use client::Client;
+pub use dispatch::Dispatch;
 pub use display::{create_display, Display};
 pub use event_loop::{resource_is_registered, EventLoop, EventLoopHandle, Global, GlobalCallback,
-                     RegisterStatus, State, StateToken};
+                     Queue, QueueToken, RegisterStatus, State, StateEventLoop, StateToken};
 pub use generated::interfaces as protocol_interfaces;
 pub use generated::server as protocol;
+pub use user_data::UserData;
+#[cfg(feature = "native_lib")]
 use wayland_sys::common::{wl_argument, wl_interface};
+#[cfg(feature = "native_lib")]
 use wayland_sys::server::*;
 
 mod client;
+mod dispatch;
 mod display;
 mod event_loop;
 mod event_sources;
+mod imp;
+mod user_data;
 
 pub mod sources {
     //! Secondary event sources
@@ -138,6 +200,7 @@ pub mod sources {
 /// working on wayland objects.
 pub unsafe trait Resource {
     /// Pointer to the underlying wayland proxy object
+    #[cfg(feature = "native_lib")]
     fn ptr(&self) -> *mut wl_resource;
     /// Create an instance from a wayland pointer
     ///
@@ -147,6 +210,7 @@ pub unsafe trait Resource {
     ///
     /// The library will take control of the object (notably
     /// overwrite its user_data).
+    #[cfg(feature = "native_lib")]
     unsafe fn from_ptr_new(*mut wl_resource) -> Self;
     /// Create an instance from a wayland pointer
     ///
@@ -155,9 +219,20 @@ pub unsafe trait Resource {
     /// resource is already managed by it or not. If it is not, this
     /// resource will be considered as "unmanaged", and should then
     /// be handled with care.
+    #[cfg(feature = "native_lib")]
     unsafe fn from_ptr_initialized(*mut wl_resource) -> Self;
     /// Pointer to the interface representation
+    #[cfg(feature = "native_lib")]
     fn interface_ptr() -> *const wl_interface;
+    /// Id of this object on the wire, in the `rust_impl` backend
+    ///
+    /// Client-allocated ids live in `imp::rust_impl::CLIENT_ID_MIN..=CLIENT_ID_MAX`,
+    /// server-allocated ones start at `imp::rust_impl::SERVER_ID_MIN`.
+    #[cfg(feature = "rust_impl")]
+    fn object_id(&self) -> u32;
+    /// The client that owns this resource, in the `rust_impl` backend
+    #[cfg(feature = "rust_impl")]
+    fn client(&self) -> Client;
     /// Internal wayland name of this interface
     fn interface_name() -> &'static str;
     /// Max version of this interface supported
@@ -172,35 +247,63 @@ pub unsafe trait Resource {
     fn equals(&self, &Self) -> bool;
     /// Set a pointer associated as user data on this resource
     ///
+    /// This is only meant for interfacing with C code through the
+    /// `native_lib` backend; from Rust, use `data`/`init_data` instead,
+    /// which are safe and do not require you to manage the pointee's
+    /// lifetime yourself.
+    ///
     /// All handles to the same wayland object share the same user data pointer.
     ///
     /// The get/set operations are atomic, no more guarantee is given. If you need
     /// to synchronise access to this data, it is your responsibility to add a Mutex
     /// or any other similar mechanism.
+    #[cfg(feature = "native_lib")]
     fn set_user_data(&self, ptr: *mut ());
     /// Get the pointer associated as user data on this resource
     ///
+    /// See `set_user_data`: only meant for `native_lib` C interop.
+    ///
     /// All handles to the same wayland object share the same user data pointer.
     ///
     /// See `set_user_data` for synchronisation guarantee.
+    #[cfg(feature = "native_lib")]
     fn get_user_data(&self) -> *mut ();
+    /// The typed user data slot backing `data`/`init_data`
+    #[doc(hidden)]
+    fn user_data(&self) -> &UserData;
+    /// Access the value previously stored by `init_data`
+    ///
+    /// Returns `None` if no value was ever stored, or if it was stored
+    /// with a different concrete type than `T`.
+    fn data<T: 'static + Send + Sync>(&self) -> Option<&T> {
+        self.user_data().get::<T>()
+    }
+    /// Initialize this resource's user data, computed from `init`
+    ///
+    /// Does nothing if the user data was already initialized: it can only
+    /// be set once, typically right after the resource is registered with
+    /// an implementation. The value is dropped automatically together with
+    /// the resource.
+    fn init_data<T, F>(&self, init: F)
+    where
+        T: 'static + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        self.user_data().set(init);
+    }
     /// Posts a protocol error to this resource
     ///
     /// The error code can be obtained from the various `Error` enums of the protocols.
     ///
     /// An error is fatal to the client that caused it.
     fn post_error(&self, error_code: u32, msg: String) {
-        // If `str` contains an interior null, the actuall transmitted message will
-        // be truncated at this point.
+        #[cfg(feature = "native_lib")]
         unsafe {
-            let cstring = ::std::ffi::CString::from_vec_unchecked(msg.into());
-            ffi_dispatch!(
-                WAYLAND_SERVER_HANDLE,
-                wl_resource_post_error,
-                self.ptr(),
-                error_code,
-                cstring.as_ptr()
-            )
+            imp::native::resource_post_error(self.ptr(), error_code, msg);
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            self.client().post_error(self.object_id(), error_code, msg);
         }
     }
     /// Clone this resource handle
@@ -228,16 +331,18 @@ pub unsafe trait Resource {
     /// Returns `true` if both are alive and belong to the same client, `false`
     /// otherwise.
     fn same_client_as<R: Resource>(&self, other: &R) -> bool {
-        // comparing client pointers for equality is only meaningfull
+        // comparing clients for equality is only meaningfull
         // if resources are alive
         if !(self.status() == Liveness::Alive && other.status() == Liveness::Alive) {
-            false
-        } else {
-            let my_client =
-                unsafe { ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_client, self.ptr()) };
-            let other_client =
-                unsafe { ffi_dispatch!(WAYLAND_SERVER_HANDLE, wl_resource_get_client, other.ptr()) };
-            my_client == other_client
+            return false;
+        }
+        #[cfg(feature = "native_lib")]
+        unsafe {
+            imp::native::resource_same_client(self.ptr(), other.ptr())
+        }
+        #[cfg(feature = "rust_impl")]
+        {
+            self.client() == other.client()
         }
     }
 }
@@ -246,9 +351,31 @@ pub unsafe trait Resource {
 pub unsafe trait Implementable<ID: 'static>: Resource {
     /// The type containing the implementation for the event callbacks
     type Implementation: PartialEq + Copy + 'static;
+    /// Decode a raw incoming message and invoke the matching callback of
+    /// `implementation`, with `idata` as its implementation data
+    ///
+    /// This is called by `EventLoopHandle::register`'s dispatcher for every
+    /// request targeting this resource; under `native_lib` the arguments
+    /// have already been decoded by `libwayland-server.so` itself, while
+    /// under `rust_impl` `args` is the request's raw, not-yet-decoded body,
+    /// since only this method (generated from the interface's XML
+    /// signature) knows how to split it into typed arguments.
+    ///
+    /// `evlh` is the handle of the event loop currently dispatching this
+    /// request, so a generated body can decode `args` into a `Dispatch<I>`
+    /// request type and call `Dispatch::request` on `evlh.dispatch_state()`.
     #[doc(hidden)]
-    unsafe fn __dispatch_msg(&self, client: &Client, opcode: u32, args: *const wl_argument)
+    #[cfg(feature = "native_lib")]
+    unsafe fn __dispatch_msg(&self, implementation: &Self::Implementation, idata: &mut ID,
+                             client: &Client, opcode: u32, args: *const wl_argument,
+                             evlh: &mut EventLoopHandle)
                              -> Result<(), ()>;
+    #[doc(hidden)]
+    #[cfg(feature = "rust_impl")]
+    fn __dispatch_msg(&self, implementation: &Self::Implementation, idata: &mut ID,
+                       client: &Client, opcode: u16, args: &[u8],
+                       evlh: &mut EventLoopHandle)
+                       -> Result<(), ()>;
 }
 
 /// Possible outcome of the call of a event on a resource
@@ -0,0 +1,99 @@
+//! Type-safe, RAII-managed user data
+//!
+//! This replaces the raw `*mut ()` user data pointer with a small
+//! type-erased box: the payload is boxed once, its concrete type is
+//! remembered by `Any`, and trying to read it back as the wrong type
+//! returns `None` instead of triggering undefined behaviour. The payload
+//! is freed automatically together with the `UserData` it lives in, which
+//! is itself tied to the lifetime of the resource it is attached to.
+
+use std::any::Any;
+use std::sync::Mutex;
+
+/// A single slot of type-erased, lazily-initialized user data
+///
+/// Every `Resource` owns one of these. Use `Resource::data` to read the
+/// payload back, and `Resource::init_data` to set it.
+pub struct UserData {
+    inner: Mutex<Option<Box<Any + Send + Sync>>>,
+}
+
+impl UserData {
+    /// Create a new, empty slot
+    pub fn new() -> UserData {
+        UserData {
+            inner: Mutex::new(None),
+        }
+    }
+
+    /// Initialize the payload, computed from `init`
+    ///
+    /// Does nothing if the payload was already initialized: a resource's
+    /// user data can only be set once, by whichever call first initializes
+    /// it.
+    pub fn set<T, F>(&self, init: F)
+    where
+        T: 'static + Send + Sync,
+        F: FnOnce() -> T,
+    {
+        let mut guard = self.inner.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(Box::new(init()) as Box<Any + Send + Sync>);
+        }
+    }
+
+    /// Access the payload, if it was set and is of type `T`
+    ///
+    /// Returns `None` if no payload was ever set, or if it was set with a
+    /// different concrete type than `T`.
+    pub fn get<T: 'static + Send + Sync>(&self) -> Option<&T> {
+        let guard = self.inner.lock().unwrap();
+        let ptr = match guard.as_ref().and_then(|b| b.downcast_ref::<T>()) {
+            Some(v) => v as *const T,
+            None => return None,
+        };
+        // SAFETY: once set, a payload is never replaced nor moved for the
+        // lifetime of this `UserData`, so the pointer stays valid for as
+        // long as `self` does.
+        Some(unsafe { &*ptr })
+    }
+}
+
+impl Default for UserData {
+    fn default() -> UserData {
+        UserData::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_before_set_is_none() {
+        let data = UserData::new();
+        assert!(data.get::<u32>().is_none());
+    }
+
+    #[test]
+    fn set_then_get_returns_the_value() {
+        let data = UserData::new();
+        data.set(|| 42u32);
+        assert_eq!(data.get::<u32>(), Some(&42));
+    }
+
+    #[test]
+    fn get_with_the_wrong_type_is_none() {
+        let data = UserData::new();
+        data.set(|| 42u32);
+        assert!(data.get::<String>().is_none());
+    }
+
+    #[test]
+    fn set_is_a_no_op_once_already_initialized() {
+        let data = UserData::new();
+        data.set(|| 1u32);
+        data.set(|| 2u32);
+        assert_eq!(data.get::<u32>(), Some(&1));
+    }
+}